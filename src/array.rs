@@ -2,15 +2,14 @@
 //!
 //! # Notes
 //!
-//! The number of entries allowed is defined by Cargo features. The
-//! default is 32 as this seems a reasonable comprimise between the
-//! size of the array and utility. Each array entry costs two words of
-//! space for the closure reference. Thus a full array of 256 entries
-//! on a 32-bit architecture costs 2048 bytes of memory, which can be
-//! quite a lot on resource constrained devices.
-//!
-//! One day, when const-generics are stabilized, this will be more
-//! elegant.
+//! The number of entries is a const generic parameter, `N`, defaulting
+//! to 32 as a reasonable comprimise between the size of the array and
+//! utility. Each array entry costs two words of space for the closure
+//! reference. Thus a full array of 256 entries on a 32-bit
+//! architecture costs 2048 bytes of memory, which can be quite a lot
+//! on resource constrained devices. Pick whatever `N` fits your
+//! target, e.g. `HandlerArray<'static, 8>`, without recompiling the
+//! crate with a different feature flag.
 //!
 //! # Examples
 //!
@@ -51,54 +50,34 @@ use crate::Handler;
 
 use core::cell::UnsafeCell;
 
-// Define features for the underlying array size so that we can
-// statically allocate it.
-// TODO: Use const generics when available.
-#[cfg(feature = "isr-8")]
-const NR_ISR: usize = 8;
-#[cfg(feature = "isr-16")]
-const NR_ISR: usize = 16;
-#[cfg(feature = "isr-32")]
-const NR_ISR: usize = 32;
-#[cfg(feature = "isr-64")]
-const NR_ISR: usize = 64;
-#[cfg(feature = "isr-128")]
-const NR_ISR: usize = 128;
-#[cfg(feature = "isr-256")]
-const NR_ISR: usize = 256;
-
 /// Safely use `Handler`s by enclosing them in an array.
 ///
 /// This type provides a safe wrapper around `Handler` by ensuring
 /// that closures are swapped safely using critical sections, and that
 /// the lifetime of those handlers is sufficient by using the inner
 /// scope of `with_overrides`/`lock_overrides`.
+///
+/// `N` is the number of interrupt handler slots in the array and
+/// defaults to 32. Pick whatever size fits your target, e.g.
+/// `HandlerArray<'static, 8>` for a small part with few vectors.
 #[derive(Debug)]
-pub struct HandlerArray<'a> {
-    h: UnsafeCell<[Handler<'a>; NR_ISR]>,
+pub struct HandlerArray<'a, const N: usize = 32> {
+    h: UnsafeCell<[Handler<'a>; N]>,
 }
 
-impl<'a> HandlerArray<'a> {
+impl<'a, const N: usize> HandlerArray<'a, N> {
     /// Create a new `HandlerArray` filled with no-op handlers.
     #[cfg(feature = "const-fn")]
     pub const fn new() -> Self {
         Self {
-            h: UnsafeCell::new([Handler::new(); NR_ISR]),
+            h: UnsafeCell::new([Handler::new(); N]),
         }
     }
 
     #[cfg(not(feature = "const-fn"))]
     pub fn new() -> Self {
-        let h = {
-            let mut ui_h: [core::mem::MaybeUninit<Handler>; NR_ISR] =
-                unsafe { core::mem::MaybeUninit::uninit().assume_init() };
-            for h in &mut ui_h[..] {
-                unsafe { core::ptr::write(h.as_mut_ptr(), Handler::new()) }
-            }
-            unsafe { core::mem::transmute(ui_h) }
-        };
         Self {
-            h: UnsafeCell::new(h),
+            h: UnsafeCell::new(core::array::from_fn(|_| Handler::new())),
         }
     }
 
@@ -118,29 +97,71 @@ impl<'a> HandlerArray<'a> {
         F: FnMut() + Send + 'a,
         CS: CriticalSection,
     {
-        cs.with_lock(|| unsafe { (*self.h.get())[nr].replace(f) });
+        debug_assert!(nr < N, "handler index {} out of bounds (N = {})", nr, N);
+        cs.with_lock(|| unsafe {
+            let h = &(*self.h.get())[nr];
+            h.set_lockdep_slot(nr);
+            h.replace(f)
+        });
     }
 
-    /// Call the handler for entry `nr`.
+    /// Call the handler for entry `nr` without synchronizing with any
+    /// other core.
+    ///
+    /// This is safe, and free of any locking overhead, on a
+    /// single-core target: there, `replace` only has to mask *this*
+    /// core's interrupts to exclude every possible caller of `call`.
+    /// It is **not** SMP-safe: on a target with more than one core,
+    /// `call` running on one core while `replace` runs concurrently on
+    /// another, synchronized only by a [`SpinLocker`](crate::cs::SpinLocker),
+    /// is a data race, because this function never touches that
+    /// lock. Use [`lock_call`](Self::lock_call) with the same locker
+    /// you pass to `lock_register`/`lock_overrides` if more than one
+    /// core can reach this array.
     pub fn call(&self, nr: usize) {
+        debug_assert!(nr < N, "handler index {} out of bounds (N = {})", nr, N);
         // Unsafe: there's always a valid handler to call except for
         // when it's being actively replaced. As long as that happens
         // while in a critical section, there's no risk of data races.
-        unsafe { (*self.h.get())[nr].call() }
+        unsafe {
+            let h = &(*self.h.get())[nr];
+            h.set_lockdep_slot(nr);
+            h.call()
+        }
+    }
+
+    /// Call the handler for entry `nr`, synchronizing with `cs` first.
+    ///
+    /// Pass the very same `cs` instance (e.g. a shared
+    /// [`SpinLocker`](crate::cs::SpinLocker)) used for
+    /// `lock_register`/`lock_overrides` on this array so that a `call`
+    /// on one core can't race a `replace` on another: `call` won't
+    /// dereference the handler until it holds `cs`, the same lock
+    /// `replace` holds while swapping it out.
+    pub fn lock_call<CS>(&self, cs: &CS, nr: usize)
+    where
+        CS: CriticalSection,
+    {
+        debug_assert!(nr < N, "handler index {} out of bounds (N = {})", nr, N);
+        cs.with_lock(|| unsafe {
+            let h = &(*self.h.get())[nr];
+            h.set_lockdep_slot(nr);
+            h.call()
+        });
     }
 
     /// Create a new array for use in `f`'s scope. The existing
     /// handlers can be overridden using `register` or
     /// `lock_register`. When `f` exits, all previous handlers are
     /// restored.
-    pub fn with_overrides<'b>(&self, f: impl FnOnce(&HandlerArray<'b>)) {
+    pub fn with_overrides<'b>(&self, f: impl FnOnce(&HandlerArray<'b, N>)) {
         self.lock_overrides(&Locker::new(), f)
     }
 
     /// Same as `with_overrides` but allows you to specify your own
     /// implementation of `CriticalSection` instead of using the
     /// default.
-    pub fn lock_overrides<'b, CS>(&self, cs: &CS, f: impl FnOnce(&HandlerArray<'b>))
+    pub fn lock_overrides<'b, CS>(&self, cs: &CS, f: impl FnOnce(&HandlerArray<'b, N>))
     where
         CS: CriticalSection,
     {
@@ -151,11 +172,11 @@ impl<'a> HandlerArray<'a> {
         // Unsafe: This requires that we back up and restore the handlers
         // in the array to make sure there's always something alive in
         // whatever the real scope of `array' is.
-        let tmp: &HandlerArray<'b> = unsafe { core::mem::transmute(self) };
+        let tmp: &HandlerArray<'b, N> = unsafe { core::mem::transmute(self) };
 
         // Back up old handlers before entering inner scope so we can
         // restore them on exit.
-        let bk = HandlerArray::new();
+        let bk = HandlerArray::<'_, N>::new();
         unsafe { core::ptr::copy_nonoverlapping(tmp.h.get(), bk.h.get(), 1) }
         f(tmp);
 
@@ -163,12 +184,101 @@ impl<'a> HandlerArray<'a> {
         // data races.
         cs.with_lock(|| unsafe { core::ptr::copy_nonoverlapping(bk.h.get(), tmp.h.get(), 1) });
     }
+
+    /// Run `f` inside the critical section `cs` creates, handing it a
+    /// [`LockToken`] it can use to access any [`Guarded`] data shared
+    /// with this array's handler closures.
+    ///
+    /// Use the same `cs` you pass to [`lock_register`](Self::lock_register)
+    /// so that holding the token here really does prove no handler
+    /// closure touching the same `Guarded` can be running.
+    pub fn lock<CS, R>(&self, cs: &CS, f: impl FnOnce(LockToken<'_>) -> R) -> R
+    where
+        CS: CriticalSection,
+    {
+        cs.with_lock(|| f(LockToken::new()))
+    }
 }
 
 // Unsafe: as long as `register` and `with_overrides` use critical
 // sections appropriately, it should be safe to share this between
 // threads.
-unsafe impl<'a> Sync for HandlerArray<'a> {}
+unsafe impl<'a, const N: usize> Sync for HandlerArray<'a, N> {}
+
+/// Proof that the critical section guarding a [`HandlerArray`]'s
+/// registrations is currently held, and so no handler closure sharing
+/// a [`Guarded`] with that array can be running concurrently.
+///
+/// Only producible by [`HandlerArray::lock`], or, from inside a
+/// registered handler closure itself, by the `unsafe`
+/// [`LockToken::assume_held`].
+pub struct LockToken<'t> {
+    _not_send_sync: core::marker::PhantomData<*const ()>,
+    _lifetime: core::marker::PhantomData<&'t ()>,
+}
+
+impl<'t> LockToken<'t> {
+    fn new() -> Self {
+        Self {
+            _not_send_sync: core::marker::PhantomData,
+            _lifetime: core::marker::PhantomData,
+        }
+    }
+
+    /// Assert that the relevant critical section is held without
+    /// actually acquiring one.
+    ///
+    /// # Safety
+    ///
+    /// Only call this from inside a handler closure registered with
+    /// `register`/`lock_register`, and only if every call to that
+    /// handler is reached through [`HandlerArray::call`] on a
+    /// single-core target, or through [`HandlerArray::lock_call`] with
+    /// the same locker used to guard `Guarded` access elsewhere.
+    /// `replace` can't run concurrently with the closure it's
+    /// replacing, so for as long as a registered closure is executing,
+    /// no other code synchronized by the *same* lock can be
+    /// concurrently holding it -- which is exactly what this token
+    /// asserts. That guarantee does **not** hold for a handler called
+    /// through the lock-free `HandlerArray::call` on an SMP target: a
+    /// `lock`/`lock_call` on another core can be running at the same
+    /// time, so `assume_held` there would be a lie.
+    pub unsafe fn assume_held() -> Self {
+        Self::new()
+    }
+}
+
+/// Data co-owned by main-loop code and a [`HandlerArray`]'s handler
+/// closures, reachable only while a [`LockToken`] proves the critical
+/// section guarding that array's registrations is held.
+///
+/// This is the crate's answer to the `Mutex<RefCell<Option<_>>>`
+/// boilerplate described in the [`handler`](crate::handler) module's
+/// motivation, for the common case where the same state is read and
+/// written both inside and outside the handler: no run-time checks,
+/// just a token you can only get by holding the lock.
+pub struct Guarded<T> {
+    v: UnsafeCell<T>,
+}
+
+impl<T> Guarded<T> {
+    /// Wrap `v` for guarded access.
+    pub const fn new(v: T) -> Self {
+        Self { v: UnsafeCell::new(v) }
+    }
+
+    /// Access the guarded data. Safe because holding `token` proves
+    /// nothing else can be concurrently accessing it.
+    pub fn access<R>(&self, _token: &LockToken<'_>, f: impl FnOnce(&mut T) -> R) -> R {
+        // Safety: `_token` proves the critical section guarding this
+        // data is held (or, from a handler closure, that one can't be
+        // concurrently held elsewhere), so `&mut *self.v.get()` can't
+        // alias a reference handed out by another `access` call.
+        f(unsafe { &mut *self.v.get() })
+    }
+}
+
+unsafe impl<T: Send> Sync for Guarded<T> {}
 
 #[cfg(test)]
 mod test {
@@ -180,7 +290,7 @@ mod test {
         let mut cl = || unsafe { CALLS += 1 };
         let cl_ref = &mut cl;
 
-        let ht = HandlerArray::new();
+        let ht: HandlerArray = HandlerArray::new();
         ht.with_overrides(|t| {
             t.register(0, cl_ref);
             ht.call(0);
@@ -189,4 +299,17 @@ mod test {
         ht.call(0);
         unsafe { assert_eq!(CALLS, 1) };
     }
+
+    #[test]
+    fn guarded_access() {
+        let ht: HandlerArray = HandlerArray::new();
+        let guarded = Guarded::new(0u32);
+
+        ht.lock(&crate::cs::Locker::new(), |token| {
+            guarded.access(&token, |v| *v += 1);
+        });
+        ht.lock(&crate::cs::Locker::new(), |token| {
+            guarded.access(&token, |v| assert_eq!(*v, 1));
+        });
+    }
 }
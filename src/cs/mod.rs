@@ -18,6 +18,15 @@
 //! However, if you are going to implement your own `CriticalSection`,
 //! you need to be aware of this limitation and its rationale to avoid
 //! getting into trouble.
+//!
+//! `Locker`, the default, only masks the current core's interrupts,
+//! which is all a single-core target needs. On SMP targets, pass
+//! [`SpinLocker`] to `lock_register`/`lock_overrides` *and* to
+//! `HandlerArray::lock_call` instead: `SpinLocker` only protects you if
+//! every call to a shared `HandlerArray`, reads and writes alike, goes
+//! through it. `HandlerArray::call` never touches any lock and stays
+//! correct only because of the single-core argument above, so it is
+//! not safe to mix with `SpinLocker` across cores.
 
 /// Generic trait which supplies the ability to create a critical
 /// section.
@@ -34,5 +43,7 @@ pub trait CriticalSection {
     path = "dummy.rs"
 )]
 mod csimpl;
+mod spin;
 
 pub use csimpl::Locker;
+pub use spin::SpinLocker;
@@ -0,0 +1,78 @@
+//! SMP-safe critical section.
+
+use super::{CriticalSection, Locker};
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// A [`CriticalSection`] that stays safe on multi-core targets.
+///
+/// The platform `Locker` (backed by `interrupt::free` or equivalent)
+/// only masks the *local* core's interrupts, so on an SMP target
+/// (multi-hart RISC-V, Cortex-A/R, ...) two cores can still race a
+/// `HandlerArray` update against a `call` on another core. `SpinLocker`
+/// closes that gap by combining local interrupt masking with a
+/// test-and-set spinlock: `with_lock` disables local interrupts, spins
+/// until it acquires the lock, runs `f`, releases the lock, and only
+/// then restores the interrupt state.
+///
+/// # Note
+///
+/// The same `SpinLocker` instance must guard every core that touches
+/// a given `HandlerArray`; two different instances don't know about
+/// each other and the spinlock can't help you. It also only protects
+/// calls that actually go through it: on the array, that means both
+/// `lock_register`/`lock_overrides` *and* `lock_call` -- the
+/// lock-free `HandlerArray::call` doesn't participate and will still
+/// race a concurrent `replace` on another core.
+pub struct SpinLocker {
+    inner: Locker,
+    locked: AtomicBool,
+}
+
+impl SpinLocker {
+    /// Create a new, unlocked `SpinLocker`.
+    pub const fn new() -> Self {
+        Self {
+            inner: Locker::new(),
+            locked: AtomicBool::new(false),
+        }
+    }
+}
+
+impl CriticalSection for SpinLocker {
+    fn with_lock<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        self.inner.with_lock(|| {
+            while self
+                .locked
+                .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                core::hint::spin_loop();
+            }
+            let r = f();
+            self.locked.store(false, Ordering::Release);
+            r
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn with_lock_runs_f_and_releases() {
+        let locker = SpinLocker::new();
+
+        let result = locker.with_lock(|| {
+            assert!(locker.locked.load(Ordering::Relaxed));
+            2 + 2
+        });
+
+        assert_eq!(result, 4);
+        assert!(!locker.locked.load(Ordering::Relaxed));
+    }
+}
@@ -32,7 +32,9 @@
 //! means that the closure you supply it must also be effectively
 //! `static` or replaced with a longer-lived closure before it goes
 //! out of scope. `Handler::default_handler()` is provided for this
-//! purpose.
+//! purpose. See the [`pin`](crate::pin) module for an alternative
+//! that avoids the `static mut` and the `unsafe` call to `replace`
+//! altogether.
 //!
 //! Additionally, replacement of an interrupt handler's closure may
 //! race with the calling of the interrupt handler's closure (i.e.,
@@ -44,6 +46,13 @@
 //! makes no assumptions about the environment in which it will be
 //! used, this cannot be done for you.
 //!
+//! Enable the `lockdep` feature to turn a replace/call race from
+//! silent UB into a loud panic during testing: with it on, every
+//! `Handler` tracks whether its closure is currently executing and
+//! `replace` panics, naming the offending slot, if it is. The feature
+//! is off by default so the only overhead of `call` in production
+//! remains calling the closure itself.
+//!
 //! # Examples
 //!
 //! This example for an ARM Cortex-M system demonstrates safe usage by
@@ -90,18 +99,35 @@ use core::cell::UnsafeCell;
 #[cfg(not(feature = "const-fn"))]
 use core::ptr::NonNull;
 
+#[cfg(feature = "lockdep")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 #[cfg(feature = "const-fn")]
 pub struct Handler<'a> {
     // Handler that will be executed on `call`.
     h: *mut dyn FnMut(),
     lifetime: core::marker::PhantomData<&'a dyn FnMut()>,
+    #[cfg(feature = "lockdep")]
+    active: AtomicUsize,
+    #[cfg(feature = "lockdep")]
+    slot: AtomicUsize,
 }
 #[cfg(not(feature = "const-fn"))]
 pub struct Handler<'a> {
     // Handler that will be executed on `call`.
     h: UnsafeCell<Option<NonNull<dyn FnMut() + 'a>>>,
+    #[cfg(feature = "lockdep")]
+    active: AtomicUsize,
+    #[cfg(feature = "lockdep")]
+    slot: AtomicUsize,
 }
 
+// Sentinel `slot` value meaning "no per-slot identifier has been set",
+// e.g. for a `Handler` used on its own rather than through a
+// `HandlerArray`.
+#[cfg(feature = "lockdep")]
+const NO_SLOT: usize = usize::MAX;
+
 impl<'a> Handler<'a> {
     /// Returns a new Handler that initially does nothing when
     /// called. Override its behavior by using `replace`.
@@ -110,16 +136,36 @@ impl<'a> Handler<'a> {
         Self {
             h: &Self::default_handler,
             lifetime: core::marker::PhantomData,
+            #[cfg(feature = "lockdep")]
+            active: AtomicUsize::new(0),
+            #[cfg(feature = "lockdep")]
+            slot: AtomicUsize::new(NO_SLOT),
         }
     }
 
     #[cfg(not(feature = "const-fn"))]
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
             h: UnsafeCell::new(None),
+            #[cfg(feature = "lockdep")]
+            active: AtomicUsize::new(0),
+            #[cfg(feature = "lockdep")]
+            slot: AtomicUsize::new(NO_SLOT),
         }
     }
 
+    /// Associate this handler with a stable per-slot identifier, e.g.
+    /// its index in a `HandlerArray`, so that a `lockdep` panic can
+    /// name exactly which slot was misused. A no-op unless the
+    /// `lockdep` feature is enabled.
+    #[cfg(feature = "lockdep")]
+    pub fn set_lockdep_slot(&self, slot: usize) {
+        self.slot.store(slot, Ordering::Relaxed);
+    }
+    #[cfg(not(feature = "lockdep"))]
+    #[inline(always)]
+    pub fn set_lockdep_slot(&self, _slot: usize) {}
+
     /// Replace the behavior of this handler with `f`.
     ///
     /// # Safety
@@ -127,7 +173,29 @@ impl<'a> Handler<'a> {
     /// There is no exclusion on replacing the handler's behavior
     /// while it is being executed. It is your responsibility to make
     /// sure that it's not being executed when you call `replace`.
+    ///
+    /// With the `lockdep` feature enabled, this is enforced: if a
+    /// `call` is on the stack when `replace` runs, `replace` panics
+    /// instead of racing it.
     pub unsafe fn replace(&self, f: &mut (dyn FnMut() + Send + 'a)) {
+        #[cfg(feature = "lockdep")]
+        {
+            let depth = self.active.load(Ordering::Acquire);
+            if depth != 0 {
+                let slot = self.slot.load(Ordering::Relaxed);
+                if slot == NO_SLOT {
+                    panic!(
+                        "Handler::replace raced Handler::call (reentrant depth {})",
+                        depth
+                    );
+                } else {
+                    panic!(
+                        "Handler::replace raced Handler::call on slot {} (reentrant depth {})",
+                        slot, depth
+                    );
+                }
+            }
+        }
         #[cfg(feature = "const-fn")]
         {
             self.h = core::mem::transmute::<_, &'a _>(f);
@@ -148,6 +216,9 @@ impl<'a> Handler<'a> {
     /// closure is being looked up. You need to ensure that `replace`
     /// and `call` can not occur at the same time.
     pub unsafe fn call(&self) {
+        #[cfg(feature = "lockdep")]
+        self.active.fetch_add(1, Ordering::Acquire);
+
         #[cfg(feature = "const-fn")]
         {
             let f: &mut dyn FnMut() = &mut *(self.h as *mut dyn FnMut());
@@ -158,6 +229,9 @@ impl<'a> Handler<'a> {
             let h: Option<NonNull<dyn FnMut()>> = *self.h.get();
             h.map(|mut f| (f.as_mut())());
         }
+
+        #[cfg(feature = "lockdep")]
+        self.active.fetch_sub(1, Ordering::Release);
     }
 
     /// Do nothing handler. Needed by `call` until `replace` is used
@@ -219,4 +293,23 @@ mod test {
             handler.call()
         }
     }
+
+    #[test]
+    #[cfg(feature = "lockdep")]
+    #[should_panic(expected = "raced Handler::call")]
+    fn lockdep_panics_on_replace_during_call() {
+        lazy_static! {
+            static ref HANDLER: Handler<'static> = Handler::new();
+        }
+
+        unsafe {
+            HANDLER.replace(&mut || {
+                // Replacing while this closure is on the stack, i.e.
+                // while `call` below is still running it, must panic
+                // with the `lockdep` feature on.
+                HANDLER.replace(&mut || ());
+            });
+            HANDLER.call();
+        }
+    }
 }
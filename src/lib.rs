@@ -10,7 +10,8 @@
 //! safe usage.
 //!
 //! The [`handler`](handler) module contains the underyling, unsafe
-//! implementation.
+//! implementation. The [`pin`](pin) module offers a safe,
+//! `static mut`-free alternative built on pinning.
 //!
 //! Critical section support is supplied by the [`cs` module](cs).
 
@@ -20,6 +21,8 @@
 pub mod array;
 pub mod cs;
 pub mod handler;
+pub mod pin;
 
 pub use array::HandlerArray;
 pub use handler::Handler;
+pub use pin::PinHandler;
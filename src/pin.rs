@@ -0,0 +1,237 @@
+//! Pinned, `static mut`-free handler registration.
+//!
+//! [`handler`](crate::handler) needs every closure it calls to live at
+//! a stable address for as long as the handler might fire, which
+//! today means a `static mut Handler` plus an `unsafe { HANDLER.replace(...)
+//! }` at startup, relying on the programmer to get it right. This
+//! module borrows the kernel's pin-init approach instead: build the
+//! handler's backing closure in place, pin it so it can never move or
+//! be dropped again, and let the type system carry the
+//! address-stability invariant instead of a doc comment.
+//!
+//! # Examples
+//!
+//! ```
+//! use clint::pin::PinHandler;
+//! use clint::pin_handler;
+//!
+//! static HANDLER: PinHandler<'static> = PinHandler::uninit();
+//!
+//! fn main() {
+//!     let mut x: u32 = 0;
+//!
+//!     // No `unsafe` here: `pin_handler!` pins the closure in place
+//!     // and wires `HANDLER` to it for us.
+//!     pin_handler!(&HANDLER, move || x += 1);
+//!
+//!     HANDLER.call_static();
+//!     HANDLER.call_static();
+//! }
+//! ```
+
+use crate::Handler;
+
+use core::cell::UnsafeCell;
+use core::marker::PhantomPinned;
+use core::mem::MaybeUninit;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Returned by [`PinHandler::set_once`] when a handler has already
+/// been wired to a closure.
+#[derive(Debug)]
+pub struct AlreadyInitialized;
+
+/// A [`Handler`] that is only ever reached through `Pin<&PinHandler>`.
+///
+/// Because a pinned `PinHandler` can't move, the closure it was wired
+/// to by [`set_once`](PinHandler::set_once) can't either, so
+/// [`call`](PinHandler::call) needs no `unsafe` at the call site: the
+/// address-stability invariant `Handler` documents is upheld by the
+/// type system rather than by convention.
+pub struct PinHandler<'a> {
+    h: Handler<'a>,
+    initialized: AtomicBool,
+    _pin: PhantomPinned,
+}
+
+impl<'a> PinHandler<'a> {
+    /// Create an uninitialized `PinHandler`. Use [`set_once`](Self::set_once),
+    /// or the [`pin_handler!`](crate::pin_handler) macro, to wire it
+    /// to a closure.
+    ///
+    /// `PinHandler` exists to live in a `static`, so this is `const`
+    /// in both `const-fn` arms, as is `Handler::new`:
+    /// `Handler::new`'s `not(const-fn)` body only calls
+    /// `UnsafeCell::new`/`AtomicUsize::new`, which are const on every
+    /// Rust version this crate supports.
+    pub const fn uninit() -> Self {
+        Self {
+            h: Handler::new(),
+            initialized: AtomicBool::new(false),
+            _pin: PhantomPinned,
+        }
+    }
+
+    /// Call the pinned handler.
+    pub fn call(self: Pin<&Self>) {
+        // Safety: `self` can't move or be dropped now that it's
+        // pinned, so the closure `set_once` wired it to (also pinned,
+        // alongside or before it) is still at the address `Handler`
+        // recorded, and `set_once` only ever succeeds once, so no
+        // replace can be racing this call.
+        unsafe { self.get_ref().h.call() }
+    }
+
+    /// Convenience for a `PinHandler` stored in a genuine `'static`
+    /// item: `static`s never move, so a plain `&'static self` is
+    /// already as good as `Pin<&'static Self>`.
+    pub fn call_static(&'static self) {
+        // Safety: `self` is a `'static` reference, so it names a
+        // `static` item, which the language guarantees is never moved
+        // or dropped.
+        unsafe { Pin::new_unchecked(self) }.call()
+    }
+
+    /// Wire this handler to call `f`, unless it has already been
+    /// initialized.
+    ///
+    /// Note that the `'a` bound on `f` is on the closure *type*, not
+    /// on how long the `&mut` borrow itself has to last: exactly like
+    /// [`Handler::replace`], you can pass a short-lived `&mut` to data
+    /// that doesn't borrow anything shorter than `'a` (e.g. a `move`
+    /// closure that owns everything it touches).
+    ///
+    /// # Safety
+    /// The pointee of `f` must be valid for as long as `self` is.
+    /// [`pin_handler!`](crate::pin_handler) guarantees this by pinning
+    /// the closure right alongside (or longer-lived than) the handler
+    /// itself.
+    pub unsafe fn set_once(
+        self: Pin<&Self>,
+        f: &mut (dyn FnMut() + Send + 'a),
+    ) -> Result<(), AlreadyInitialized> {
+        if self.initialized.swap(true, Ordering::AcqRel) {
+            return Err(AlreadyInitialized);
+        }
+        self.get_ref().h.replace(f);
+        Ok(())
+    }
+}
+
+unsafe impl Sync for PinHandler<'_> {}
+
+/// Storage for a closure, built in place by [`pin_handler!`] and
+/// pinned alongside the [`PinHandler`] it's wired to. There's no
+/// reason to name this type directly; use the macro.
+#[doc(hidden)]
+pub struct HandlerCell<F> {
+    closure: UnsafeCell<MaybeUninit<F>>,
+    _pin: PhantomPinned,
+}
+
+impl<F> HandlerCell<F>
+where
+    F: FnMut() + Send,
+{
+    #[doc(hidden)]
+    pub const fn new(f: F) -> Self {
+        Self {
+            closure: UnsafeCell::new(MaybeUninit::new(f)),
+            _pin: PhantomPinned,
+        }
+    }
+
+    /// Wire `handler` to call the closure pinned in this cell.
+    ///
+    /// `'h` is just how long these two `Pin`ned *references* need to
+    /// be borrowed for this call (often short, e.g. when `handler`
+    /// points at a `'static` item but `self` is a local); `'a` is
+    /// `PinHandler`'s own, usually-`'static`, closure-type bound, and
+    /// is independent of `'h`. `PinHandler<'a>` is invariant in `'a`
+    /// (it holds a `Handler<'a>`), so the two must stay decoupled like
+    /// this rather than unified into one parameter.
+    #[doc(hidden)]
+    pub fn init<'h, 'a>(
+        self: Pin<&'h Self>,
+        handler: Pin<&'h PinHandler<'a>>,
+    ) -> Pin<&'h PinHandler<'a>>
+    where
+        F: 'a,
+    {
+        // Safety: `self` is pinned, so this cell's closure never
+        // moves or is dropped again; handing out a unique reference
+        // to it once, for the lifetime of this call, is sound.
+        let closure: &mut F = unsafe { (*self.get_ref().closure.get()).assume_init_mut() };
+        // Safety: the cell backing `closure` is pinned alongside (and
+        // at least as long as) `handler` by `pin_handler!`, and `F: 'a`
+        // means `closure` borrows nothing shorter-lived than `'a`
+        // either, satisfying `set_once`'s contract.
+        let _ = unsafe { handler.set_once(closure) };
+        handler
+    }
+}
+
+/// Build a closure in place, pin it, and wire a [`PinHandler`] to call
+/// it, with no `unsafe` at the call site.
+///
+/// `$handler` must be a `&'static PinHandler<'static>`, typically a
+/// reference to a `static` created with [`PinHandler::uninit`]. The
+/// closure is pinned locally, right where the macro is invoked, so
+/// the enclosing scope (commonly a `fn main() -> !` that never
+/// returns) must outlive every call to the handler.
+///
+/// # Examples
+///
+/// ```
+/// use clint::pin::PinHandler;
+/// use clint::pin_handler;
+///
+/// static HANDLER: PinHandler<'static> = PinHandler::uninit();
+///
+/// let mut x: u32 = 0;
+/// pin_handler!(&HANDLER, move || x += 1);
+/// HANDLER.call_static();
+/// ```
+#[macro_export]
+macro_rules! pin_handler {
+    ($handler:expr, $f:expr) => {{
+        let cell = $crate::pin::HandlerCell::new($f);
+        // Safety: `cell` is immediately shadowed by a `Pin` over the
+        // same binding below, so the original binding can never be
+        // moved or dropped out from under it again.
+        let cell = unsafe { ::core::pin::Pin::new_unchecked(&cell) };
+        // Safety: `$handler` names a `&'static PinHandler`, and
+        // `static`s never move.
+        let handler = unsafe { ::core::pin::Pin::new_unchecked($handler) };
+        $crate::pin::HandlerCell::init(cell, handler)
+    }};
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pin_handler_call() {
+        static HANDLER: PinHandler<'static> = PinHandler::uninit();
+        static mut X: u32 = 0;
+
+        pin_handler!(&HANDLER, || unsafe { X += 1 });
+        HANDLER.call_static();
+        HANDLER.call_static();
+        unsafe { assert_eq!(X, 2) };
+    }
+
+    #[test]
+    fn set_once_rejects_reinitialization() {
+        static HANDLER: PinHandler<'static> = PinHandler::uninit();
+
+        let mut a = || ();
+        let mut b = || ();
+        let handler = unsafe { Pin::new_unchecked(&HANDLER) };
+
+        assert!(unsafe { handler.set_once(&mut a) }.is_ok());
+        assert!(unsafe { handler.set_once(&mut b) }.is_err());
+    }
+}
@@ -4,7 +4,7 @@ use clint::cs::Locker;
 use clint::HandlerArray;
 
 fn main() {
-    let mut hs = HandlerArray::new();
+    let mut hs: HandlerArray = HandlerArray::new();
     hs.with_overrides(|new_hs| nested(new_hs));
 }
 